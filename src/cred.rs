@@ -2,7 +2,8 @@ use std::ffi::CString;
 use std::io::Write;
 use std::mem;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{ChildStdin, Command, Stdio};
+use std::ptr;
 use url::{self, UrlParser};
 
 use {raw, Error, Config, IntoCString};
@@ -13,6 +14,34 @@ pub struct Cred {
     raw: *mut raw::git_cred,
 }
 
+// Overwrite a buffer with zeroes via a volatile write the optimizer can't
+// reason away, for buffers that may hold plaintext we don't want lingering
+// in reclaimable heap memory.
+fn scrub(buf: &mut [u8]) {
+    unsafe {
+        for byte in buf.iter_mut() {
+            ptr::write_volatile(byte, 0);
+        }
+    }
+}
+
+/// A `String` that overwrites its backing memory with zeroes when dropped.
+///
+/// Credential helpers hand us plaintext passwords, and once a `Cred` has
+/// been built from them we'd rather not leave lingering copies sitting
+/// around in freed heap pages.
+struct SecureString(String);
+
+impl SecureString {
+    fn as_slice(&self) -> &str { self.0.as_slice() }
+}
+
+impl Drop for SecureString {
+    fn drop(&mut self) {
+        unsafe { scrub(self.0.as_mut_vec()) }
+    }
+}
+
 /// Management of the gitcredentials(7) interface.
 pub struct CredentialHelper {
     /// A public field representing the currently discovered username from
@@ -20,8 +49,16 @@ pub struct CredentialHelper {
     pub username: Option<String>,
     protocol: Option<String>,
     host: Option<String>,
+    path: Option<String>,
     url: String,
     commands: Vec<String>,
+    // The protocol/host/path a prior `execute()` call actually settled on,
+    // if a helper redirected the request via `url=`. `store`/`erase` prefer
+    // this over `protocol`/`host`/`path` so they target wherever the
+    // credential came from; `execute()` itself always starts over from the
+    // original `protocol`/`host`/`path` rather than building on this, so
+    // repeated calls are independent of each other.
+    redirect: Option<(Option<String>, Option<String>, Option<String>)>,
 }
 
 impl Cred {
@@ -96,6 +133,7 @@ impl Cred {
                              username: Option<&str>)
                              -> Result<Cred, Error> {
         match CredentialHelper::new(url).config(config).username(username)
+                               .platform_defaults()
                                .execute() {
             Some((username, password)) => {
                 Cred::userpass_plaintext(username.as_slice(),
@@ -149,25 +187,25 @@ impl CredentialHelper {
         let mut ret = CredentialHelper {
             protocol: None,
             host: None,
+            path: None,
             username: None,
             url: url.to_string(),
             commands: Vec::new(),
+            redirect: None,
         };
 
-        // Parse out the (protocol, host) if one is available
-        let parsed_url = UrlParser::new().scheme_type_mapper(mapper).parse(url);
-        match parsed_url {
-            Ok(url) => {
-                match url.host() {
-                    Some(&url::Host::Domain(ref s)) => ret.host = Some(s.clone()),
-                    _ => {}
-                }
-                ret.protocol = Some(url.scheme)
-            }
-            Err(..) => {}
-        };
-        return ret;
+        // Parse out the (protocol, host, path) if one is available
+        let (protocol, host, path) = CredentialHelper::parse_url(url);
+        ret.protocol = protocol;
+        ret.host = host;
+        ret.path = path;
+        ret
+    }
 
+    // Break a url down into the (protocol, host, path) triple that a
+    // credential helper is keyed/invoked on. Invalid urls are ignored,
+    // yielding all `None`.
+    fn parse_url(url: &str) -> (Option<String>, Option<String>, Option<String>) {
         fn mapper(s: &str) -> url::SchemeType {
             match s {
                 "git" => url::SchemeType::Relative(9418),
@@ -175,6 +213,25 @@ impl CredentialHelper {
                 s => url::whatwg_scheme_type_mapper(s),
             }
         }
+
+        match UrlParser::new().scheme_type_mapper(mapper).parse(url) {
+            Ok(url) => {
+                let host = match url.host() {
+                    Some(&url::Host::Domain(ref s)) => Some(s.clone()),
+                    _ => None,
+                };
+                // `serialize_path` always has a leading `/`, even for a
+                // bare host with no path (`Some("/")`). git's own
+                // `path=`/config-key convention has neither, so strip it
+                // off here rather than at every call site.
+                let path = url.serialize_path().and_then(|p| {
+                    let p = p.trim_left_matches('/');
+                    if p.is_empty() { None } else { Some(p.to_string()) }
+                });
+                (Some(url.scheme), host, path)
+            }
+            Err(..) => (None, None, None),
+        }
     }
 
     /// Set the username that this credential helper will query with.
@@ -185,14 +242,34 @@ impl CredentialHelper {
         self
     }
 
+    /// Add the platform's native credential helper, if any, to the list of
+    /// commands that will be queried.
+    ///
+    /// This appends `git-credential-osxkeychain` on macOS,
+    /// `git-credential-libsecret` on Linux, and `git-credential-manager-core`
+    /// on Windows, so that a machine with a working OS keychain can still
+    /// authenticate even when no `credential.helper` has been configured.
+    /// Helpers are queried in the order they were added, so the platform
+    /// helper follows any helpers that `config` discovered before this call.
+    /// Like those, it is silently skipped if the binary isn't installed.
+    pub fn platform_defaults(&mut self) -> &mut CredentialHelper {
+        if cfg!(target_os = "macos") {
+            self.add_command(Some("osxkeychain"));
+        } else if cfg!(target_os = "linux") {
+            self.add_command(Some("libsecret"));
+        } else if cfg!(windows) {
+            self.add_command(Some("manager-core"));
+        }
+        self
+    }
+
     /// Query the specified configuration object to discover commands to
     /// execute, usernames to query, etc.
     pub fn config(&mut self, config: &Config) -> &mut CredentialHelper {
         // Figure out the configured username/helper program.
         //
         // see http://git-scm.com/docs/gitcredentials.html#_configuration_options
-        //
-        // TODO: implement useHttpPath
+        self.config_use_http_path(config);
         if self.username.is_none() {
             self.config_username(config);
         }
@@ -200,6 +277,35 @@ impl CredentialHelper {
         self
     }
 
+    // Discover whether credentials should be keyed on the request's path in
+    // addition to its protocol and host, via `credential.useHttpPath`, the
+    // `credential.<protocol>://<host>.useHttpPath` form, or the exact
+    // per-URL `credential.<url>.useHttpPath` override (highest priority,
+    // mirroring `config_username`'s precedence).
+    //
+    // Note this can't just delegate to `url_key`, since `url_key` folds the
+    // path itself into the generated key once `self.path` is set -- and
+    // whether to use that path at all is exactly what we're figuring out
+    // here.
+    fn config_use_http_path(&mut self, config: &Config) {
+        let mut use_path = config.get_bool("credential.useHttpPath").unwrap_or(false);
+        match (&self.host, &self.protocol) {
+            (&Some(ref host), &Some(ref protocol)) => {
+                let key = format!("credential.{}://{}.useHttpPath", protocol, host);
+                if let Ok(v) = config.get_bool(key.as_slice()) {
+                    use_path = v;
+                }
+            }
+            _ => {}
+        }
+        if let Ok(v) = config.get_bool(self.exact_key("useHttpPath").as_slice()) {
+            use_path = v;
+        }
+        if !use_path {
+            self.path = None;
+        }
+    }
+
     // Configure the queried username from `config`
     fn config_username(&mut self, config: &Config) {
         let key = self.exact_key("username");
@@ -250,7 +356,12 @@ impl CredentialHelper {
     fn url_key(&self, name: &str) -> Option<String> {
         match (&self.host, &self.protocol) {
             (&Some(ref host), &Some(ref protocol)) => {
-                Some(format!("credential.{}://{}.{}", protocol, host, name))
+                Some(match self.path {
+                    Some(ref path) => {
+                        format!("credential.{}://{}/{}.{}", protocol, host, path, name)
+                    }
+                    None => format!("credential.{}://{}.{}", protocol, host, name),
+                })
             }
             _ => None
         }
@@ -259,33 +370,138 @@ impl CredentialHelper {
     /// Execute this helper, attempting to discover a username/password pair.
     ///
     /// All I/O errors are ignored, (to match git behavior), and this function
-    /// only succeeds if both a username and a password were found
-    pub fn execute(&self) -> Option<(String, String)> {
+    /// only succeeds if both a username and a password were found. A helper
+    /// may send `quit=1` to take exclusive control of the request; once
+    /// `quit` is seen no further helpers are consulted. A helper may also
+    /// send `url=`, which resets the protocol/host/path context for the
+    /// remaining helpers in the cascade but does not by itself stop it.
+    ///
+    /// If a `url=` redirect was honored, a subsequent `store` or `erase`
+    /// call targets the redirected protocol/host/path rather than the
+    /// original request, since that's where the returned credential
+    /// actually came from. This doesn't affect later calls to `execute`
+    /// itself, which always starts back over from the original request.
+    pub fn execute(&mut self) -> Option<(String, SecureString)> {
         let mut username = self.username.clone();
         let mut password = None;
+        let mut protocol = self.protocol.clone();
+        let mut host = self.host.clone();
+        let mut path = self.path.clone();
+
         for cmd in self.commands.iter() {
-            let (u, p) = self.execute_cmd(cmd.as_slice(), &username);
+            let (u, p, url, quit) = self.execute_cmd(cmd.as_slice(), &protocol,
+                                                     &host, &path, &username);
             if u.is_some() && username.is_none() {
                 username = u;
             }
             if p.is_some() && password.is_none() {
                 password = p;
             }
+            if let Some(url) = url {
+                let (new_protocol, new_host, new_path) =
+                    CredentialHelper::parse_url(url.as_slice());
+                protocol = new_protocol;
+                host = new_host;
+                path = new_path;
+            }
             if username.is_some() && password.is_some() { break }
+            if quit { break }
         }
 
+        // Remember where this run actually settled, for `store`/`erase` to
+        // target, without disturbing `protocol`/`host`/`path` themselves --
+        // a subsequent `execute()` call should start over from the original
+        // request, not continue from a prior run's redirect.
+        self.redirect = Some((protocol, host, path));
+
         match (username, password) {
             (Some(u), Some(p)) => Some((u, p)),
             _ => None,
         }
     }
 
+    /// Store a confirmed username/password pair with every configured
+    /// helper.
+    ///
+    /// This should be called once a credential returned from `execute` has
+    /// actually been accepted, so that it's remembered for next time. I/O
+    /// errors and nonzero exit statuses are ignored, as a helper may simply
+    /// not implement the `store` action.
+    pub fn store(&self, username: &str, password: &str) {
+        for cmd in self.commands.iter() {
+            self.store_cmd(cmd.as_slice(), username, password);
+        }
+    }
+
+    /// Erase any credentials previously remembered by the configured
+    /// helpers.
+    ///
+    /// This should be called once a credential returned from `execute` has
+    /// been rejected, so a stale entry isn't offered again next time.
+    pub fn erase(&self) {
+        for cmd in self.commands.iter() {
+            self.erase_cmd(cmd.as_slice());
+        }
+    }
+
+    fn store_cmd(&self, cmd: &str, username: &str, password: &str) {
+        let (protocol, host, path) = self.current_context();
+        self.run_cmd(cmd, "store", |stdin| {
+            CredentialHelper::write_context(stdin, &protocol, &host, &path,
+                                            &Some(username.to_string()));
+            let _ = writeln!(stdin, "password={}", password);
+        });
+    }
+
+    fn erase_cmd(&self, cmd: &str) {
+        let (protocol, host, path) = self.current_context();
+        self.run_cmd(cmd, "erase", |stdin| {
+            CredentialHelper::write_context(stdin, &protocol, &host, &path,
+                                            &self.username);
+        });
+    }
+
+    // The protocol/host/path that `store`/`erase` should target: wherever a
+    // prior `execute()` redirected to, or the original request otherwise.
+    fn current_context(&self) -> (Option<String>, Option<String>, Option<String>) {
+        match self.redirect {
+            Some(ref r) => r.clone(),
+            None => (self.protocol.clone(), self.host.clone(), self.path.clone()),
+        }
+    }
+
+    // Run `cmd <action>`, handing the spawned process's stdin to `write_stdin`
+    // and then ignoring whatever it produces. Used for `store` and `erase`,
+    // which unlike `get` have nothing useful to read back from stdout.
+    fn run_cmd<F>(&self, cmd: &str, action: &str, write_stdin: F)
+                 where F: FnOnce(&mut ChildStdin) {
+        let mut p = match Command::new("sh").arg("-c")
+                                            .arg(&format!("{} {}", cmd, action))
+                                            .stdin(Stdio::capture())
+                                            .stdout(Stdio::capture())
+                                            .stderr(Stdio::capture())
+                                            .spawn() {
+            Ok(p) => p,
+            Err(..) => return,
+        };
+        {
+            let stdin = p.stdin.as_mut().unwrap();
+            write_stdin(stdin);
+        }
+        let _ = p.wait_with_output();
+    }
+
     // Execute the given `cmd`, providing the appropriate variables on stdin and
     // then afterwards parsing the output into the username/password on stdout.
-    fn execute_cmd(&self, cmd: &str, username: &Option<String>)
-                   -> (Option<String>, Option<String>) {
+    //
+    // `protocol`/`host`/`path` are passed in explicitly (rather than read off
+    // `self`) since a helper earlier in the cascade may have redirected the
+    // context for the rest of the run via a `url=` response.
+    fn execute_cmd(&self, cmd: &str, protocol: &Option<String>, host: &Option<String>,
+                  path: &Option<String>, username: &Option<String>)
+                  -> (Option<String>, Option<SecureString>, Option<String>, bool) {
         macro_rules! my_try( ($e:expr) => (
-            match $e { Ok(e) => e, Err(..) => return (None, None) }
+            match $e { Ok(e) => e, Err(..) => return (None, None, None, false) }
         ) );
 
         let mut p = my_try!(Command::new("sh").arg("-c")
@@ -298,29 +514,48 @@ impl CredentialHelper {
         // stdin
         {
             let stdin = p.stdin.as_mut().unwrap();
-            match self.protocol {
-                Some(ref p) => { let _ = writeln!(stdin, "protocol={}", p); }
-                None => {}
-            }
-            match self.host {
-                Some(ref p) => { let _ = writeln!(stdin, "host={}", p); }
-                None => {}
-            }
-            match *username {
-                Some(ref p) => { let _ = writeln!(stdin, "username={}", p); }
-                None => {}
-            }
+            CredentialHelper::write_context(stdin, protocol, host, path, username);
+        }
+        let mut output = my_try!(p.wait_with_output());
+        if !output.status.success() {
+            scrub(output.stdout.as_mut_slice());
+            return (None, None, None, false)
         }
-        let output = my_try!(p.wait_with_output());
-        if !output.status.success() { return (None, None) }
         return self.parse_output(output.stdout)
     }
 
-    // Parse the output of a command into the username/password found
-    fn parse_output(&self, output: Vec<u8>) -> (Option<String>, Option<String>) {
+    // Write the `protocol=`/`host=`/`path=`/`username=` context lines that
+    // every helper invocation (get, store, erase) is fed on stdin.
+    fn write_context(stdin: &mut ChildStdin,
+                     protocol: &Option<String>, host: &Option<String>,
+                     path: &Option<String>, username: &Option<String>) {
+        match *protocol {
+            Some(ref p) => { let _ = writeln!(stdin, "protocol={}", p); }
+            None => {}
+        }
+        match *host {
+            Some(ref h) => { let _ = writeln!(stdin, "host={}", h); }
+            None => {}
+        }
+        match *path {
+            Some(ref p) => { let _ = writeln!(stdin, "path={}", p); }
+            None => {}
+        }
+        match *username {
+            Some(ref u) => { let _ = writeln!(stdin, "username={}", u); }
+            None => {}
+        }
+    }
+
+    // Parse the output of a command into the username/password found, along
+    // with any `url`/`quit` directives the helper used to steer the cascade.
+    fn parse_output(&self, mut output: Vec<u8>)
+                    -> (Option<String>, Option<SecureString>, Option<String>, bool) {
         // Parse the output of the command, looking for username/password
         let mut username = None;
         let mut password = None;
+        let mut url = None;
+        let mut quit = false;
         for line in output.split(|t| *t == b'\n') {
             let mut parts = line.splitn(1, |t| *t == b'=');
             let key = parts.next().unwrap();
@@ -331,11 +566,17 @@ impl CredentialHelper {
             };
             match key {
                 b"username" => username = Some(value),
-                b"password" => password = Some(value),
+                b"password" => password = Some(SecureString(value)),
+                b"url" => url = Some(value),
+                b"quit" => quit = value.as_slice() == "1" || value.as_slice() == "true",
                 _ => {}
             }
         }
-        (username, password)
+        // The raw buffer still holds a plaintext copy of any `password=`
+        // line we just parsed out of it; zero it before it's dropped so
+        // that copy doesn't linger in reclaimable heap memory.
+        scrub(output.as_mut_slice());
+        (username, password, url, quit)
     }
 }
 
@@ -373,6 +614,19 @@ mod test {
         assert_eq!(p.as_slice(), "b");
     }
 
+    #[test]
+    fn credential_helper_platform_defaults() {
+        // No `credential.helper` configured at all, so the only command in
+        // play is the platform default. It's unlikely to be installed in
+        // the test environment, so this should fail gracefully (matching
+        // git's "ignore helpers that fail" behavior) rather than panic.
+        let cfg = cfg! {};
+        assert!(CredentialHelper::new("https://example.com/foo/bar")
+                                 .config(&cfg)
+                                 .platform_defaults()
+                                 .execute().is_none());
+    }
+
     #[test]
     fn credential_helper2() {
         let cfg = cfg! {};
@@ -442,6 +696,152 @@ echo username=c
         assert_eq!(p.as_slice(), "b");
     }
 
+    #[test]
+    fn credential_helper_store() {
+        let td = TempDir::new("git2-rs").unwrap();
+        let out = td.path().join("out");
+        let helper = format!("!f() {{ echo \"$1\" > \"{0}\"; cat >> \"{0}\"; }}; f",
+                             out.display());
+        let cfg = cfg! { "credential.helper" => helper.as_slice() };
+
+        CredentialHelper::new("https://example.com/foo/bar")
+                         .config(&cfg)
+                         .store("user", "pass");
+
+        let mut contents = String::new();
+        File::open(&out).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(contents.starts_with("store\n"));
+        assert!(contents.contains("username=user\n"));
+        assert!(contents.contains("password=pass\n"));
+    }
+
+    #[test]
+    fn credential_helper_erase() {
+        let td = TempDir::new("git2-rs").unwrap();
+        let out = td.path().join("out");
+        let helper = format!("!f() {{ echo \"$1\" > \"{0}\"; cat >> \"{0}\"; }}; f",
+                             out.display());
+        let cfg = cfg! { "credential.helper" => helper.as_slice() };
+
+        CredentialHelper::new("https://example.com/foo/bar")
+                         .username(Some("user"))
+                         .config(&cfg)
+                         .erase();
+
+        let mut contents = String::new();
+        File::open(&out).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(contents.starts_with("erase\n"));
+        assert!(contents.contains("username=user\n"));
+        assert!(!contents.contains("password="));
+    }
+
+    #[test]
+    fn credential_helper_store_uses_redirected_context() {
+        let td = TempDir::new("git2-rs").unwrap();
+        let out = td.path().join("out");
+        let helper = format!("!f() {{ \
+                if [ \"$1\" = get ]; then \
+                    echo url=https://redirected.example/baz; \
+                    echo quit=1; \
+                    echo username=u; \
+                    echo password=p; \
+                else \
+                    echo \"$1\" > \"{0}\"; cat >> \"{0}\"; \
+                fi; }}; f", out.display());
+        let cfg = cfg! { "credential.helper" => helper.as_slice() };
+
+        let mut cred = CredentialHelper::new("https://example.com/foo/bar");
+        cred.config(&cfg);
+        let (u, p) = cred.execute().unwrap();
+        assert_eq!(u.as_slice(), "u");
+        assert_eq!(p.as_slice(), "p");
+
+        // `execute` honored the helper's `url=` redirect, so `store` should
+        // target the redirected host rather than the original one.
+        cred.store("u", "p");
+
+        let mut contents = String::new();
+        File::open(&out).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(contents.contains("protocol=https\n"));
+        assert!(contents.contains("host=redirected.example\n"));
+    }
+
+    #[test]
+    fn credential_helper_execute_resets_between_calls() {
+        let td = TempDir::new("git2-rs").unwrap();
+        let out = td.path().join("out");
+        let helper = format!("!f() {{ \
+                h=; while read l; do case \"$l\" in host=*) h=${{l#host=}};; esac; done; \
+                echo \"$h\" >> \"{0}\"; \
+                echo url=https://redirected.example/baz; \
+                echo quit=1; \
+                echo username=u; \
+                echo password=p; }}; f", out.display());
+        let cfg = cfg! { "credential.helper" => helper.as_slice() };
+
+        let mut cred = CredentialHelper::new("https://example.com/foo/bar");
+        cred.config(&cfg);
+        cred.execute().unwrap();
+        // A second `execute()` call should start over from the original
+        // request, not continue from the first call's redirect.
+        cred.execute().unwrap();
+
+        let mut contents = String::new();
+        File::open(&out).unwrap().read_to_string(&mut contents).unwrap();
+        let hosts: Vec<&str> = contents.lines().collect();
+        assert_eq!(hosts, vec!["example.com", "example.com"]);
+    }
+
+    #[test]
+    fn credential_helper_use_http_path() {
+        let cfg = cfg! {
+            "credential.https://example.com.useHttpPath" => "true",
+            "credential.helper" =>
+                "!f() { while read l; do case \"$l\" in \
+                     path=*) echo username=${l#path=};; esac; done; \
+                 echo password=b; }; f"
+        };
+        let (u, p) = CredentialHelper::new("https://example.com/foo/bar")
+                                      .config(&cfg)
+                                      .execute().unwrap();
+        // git sends `path=` without a leading slash; make sure we match.
+        assert_eq!(u.as_slice(), "foo/bar");
+        assert_eq!(p.as_slice(), "b");
+    }
+
+    #[test]
+    fn credential_helper_use_http_path_per_path_helper() {
+        // The motivating use case for `useHttpPath`: distinct credentials
+        // (and here, distinct helpers) for different paths on the same
+        // host, keyed the way gitcredentials(7) actually keys them --
+        // `credential.<protocol>://<host>/<path>.<name>`, no double slash.
+        let cfg = cfg! {
+            "credential.https://example.com.useHttpPath" => "true",
+            "credential.https://example.com/org-a.helper" =>
+                "!f() { while read l; do case \"$l\" in \
+                     path=*) echo username=${l#path=};; esac; done; \
+                 echo password=b; }; f"
+        };
+        let (u, p) = CredentialHelper::new("https://example.com/org-a")
+                                      .config(&cfg)
+                                      .execute().unwrap();
+        assert_eq!(u.as_slice(), "org-a");
+        assert_eq!(p.as_slice(), "b");
+    }
+
+    #[test]
+    fn credential_helper_no_use_http_path_by_default() {
+        let cfg = cfg! {
+            "credential.helper" =>
+                "!f() { while read l; do case \"$l\" in \
+                     path=*) echo username=HASPATH;; esac; done; \
+                 echo password=b; }; f"
+        };
+        assert!(CredentialHelper::new("https://example.com/foo/bar")
+                                 .config(&cfg)
+                                 .execute().is_none());
+    }
+
     #[cfg(unix)]
     fn chmod(path: &Path) {
         use std::os::unix::prelude::*;